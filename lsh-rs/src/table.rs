@@ -2,34 +2,171 @@ use crate::hash::Hash;
 use crate::utils::all_eq;
 use fnv::FnvHashMap as HashMap;
 use fnv::FnvHashSet as HashSet;
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+/// A stored point, dense or sparse. `VecStore`/`HashTables` are built around this enum
+/// rather than a bare `Vec<f32>` so a single bucket can hold whichever representation the
+/// vectors were indexed with, without densifying sparse input.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DataPoint {
+    Dense(Vec<f32>),
+    Sparse(SparseDataPoint),
+}
+
+impl DataPoint {
+    /// Borrow this point as a [`DataPointSlice`] without cloning the dense `Vec<f32>` or
+    /// the sparse index/value pairs.
+    pub fn as_slice(&self) -> DataPointSlice<'_> {
+        match self {
+            DataPoint::Dense(v) => DataPointSlice::Dense(v.as_slice()),
+            DataPoint::Sparse(s) => DataPointSlice::Sparse(s),
+        }
+    }
+}
+
+/// `DataPoint`, borrowed: a dense point as `&[f32]`, a sparse point as `&SparseDataPoint`.
+/// Lets callers pass a point to `delete`/`query` without cloning it into an owned
+/// `DataPoint` first.
+#[derive(Clone, Copy, Debug)]
+pub enum DataPointSlice<'a> {
+    Dense(&'a [f32]),
+    Sparse(&'a SparseDataPoint),
+}
+
+/// A high-dimensional point stored as `(index, value)` pairs, skipping implicit zeros.
+/// `indices`/`values` are kept sorted by index so equality checks and dot products can run
+/// as a single linear merge instead of a densify step. The fields are private, so outside
+/// of deserialization they are only populated by [`SparseDataPoint::new`] and that
+/// invariant can't be broken by a caller building one with unsorted pairs directly.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SparseDataPoint {
+    indices: Vec<u32>,
+    values: Vec<f32>,
+    dim: u32,
+}
+
+impl SparseDataPoint {
+    pub fn new(indices: Vec<u32>, values: Vec<f32>, dim: u32) -> SparseDataPoint {
+        let mut order: Vec<usize> = (0..indices.len()).collect();
+        order.sort_unstable_by_key(|&i| indices[i]);
+        SparseDataPoint {
+            indices: order.iter().map(|&i| indices[i]).collect(),
+            values: order.iter().map(|&i| values[i]).collect(),
+            dim,
+        }
+    }
+
+    /// Indices of the non-zero entries, sorted ascending.
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    /// Values of the non-zero entries, aligned with [`SparseDataPoint::indices`].
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    pub fn dim(&self) -> u32 {
+        self.dim
+    }
+
+    /// Dot product via a single linear merge over both (sorted) index lists: advance
+    /// whichever cursor points at the smaller index, and only accumulate where the two
+    /// share an index, so implicit zeros are skipped entirely.
+    pub fn dot(&self, other: &SparseDataPoint) -> f32 {
+        let mut sum = 0.0f32;
+        let (mut i, mut j) = (0, 0);
+        while i < self.indices.len() && j < other.indices.len() {
+            let (a, b) = (self.indices[i], other.indices[j]);
+            if a == b {
+                sum += self.values[i] * other.values[j];
+                i += 1;
+                j += 1;
+            } else if a < b {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        sum
+    }
+
+    fn sorted_pairs_eq(&self, other: &SparseDataPoint) -> bool {
+        self.indices == other.indices && self.values == other.values
+    }
+}
+
+/// Sparse-aware equality: dense points compare element-wise, sparse points compare equal
+/// iff their sorted `(index, value)` pairs match. Dense and sparse never compare equal,
+/// even when they represent the same vector, since the underlying storage differs.
+fn data_point_eq_slice(a: &DataPoint, b: DataPointSlice<'_>) -> bool {
+    match (a, b) {
+        (DataPoint::Dense(a), DataPointSlice::Dense(b)) => all_eq(a, b),
+        (DataPoint::Sparse(a), DataPointSlice::Sparse(b)) => a.sorted_pairs_eq(b),
+        _ => false,
+    }
+}
 
-pub type DataPoint = Vec<f32>;
-pub type DataPointSlice = [f32];
 /// Bucket contains indexes to VecStore
 pub type Bucket = HashSet<u32>;
+/// For a single stored index, the `(hash, hash_table)` pairs whose buckets contain it.
+/// Most points only land in a handful of tables, so this stays inline.
+type ReverseEntries = SmallVec<[(Hash, usize); 4]>;
+#[derive(Debug)]
 pub enum HashTableError {
     Failed,
     NotFound,
+    WrongMagic,
+    UnsupportedVersion,
+    /// The backing file is shorter than its own header claims, e.g. truncated by a crash
+    /// mid-write or by copying it before a flush completed.
+    Truncated,
+    Io,
+}
+
+impl From<std::io::Error> for HashTableError {
+    fn from(_: std::io::Error) -> Self {
+        HashTableError::Io
+    }
 }
 
 /// Indexible vector storage.
 /// indexes will be stored in hashtables. The original vectors can be looked up in this data structure.
+#[derive(Serialize, Deserialize)]
 struct VecStore {
-    map: Vec<DataPoint>,
+    map: Vec<Option<DataPoint>>,
+    /// Slots freed by `remove`, reused by the next `push` instead of growing `map`.
+    free: Vec<u32>,
 }
 
 impl VecStore {
     fn push(&mut self, d: DataPoint) -> u32 {
-        self.map.push(d);
+        if let Some(idx) = self.free.pop() {
+            self.map[idx as usize] = Some(d);
+            return idx;
+        }
+        self.map.push(Some(d));
         (self.map.len() - 1) as u32
     }
 
-    fn position(&self, d: &DataPointSlice) -> Option<u32> {
-        self.map.iter().position(|x| all_eq(x, d)).map(|x| x as u32)
+    /// Drop the point at `idx` and make the slot available for reuse.
+    fn remove(&mut self, idx: u32) {
+        self.map[idx as usize] = None;
+        self.free.push(idx);
+    }
+
+    fn position(&self, d: DataPointSlice<'_>) -> Option<u32> {
+        self.map
+            .iter()
+            .position(|x| x.as_ref().is_some_and(|x| data_point_eq_slice(x, d)))
+            .map(|x| x as u32)
     }
 
     fn get(&self, idx: u32) -> &DataPoint {
-        &self.map[idx as usize]
+        self.map[idx as usize]
+            .as_ref()
+            .expect("index points to a removed DataPoint")
     }
 
     fn increase_storage(&mut self, size: usize) {
@@ -42,66 +179,229 @@ impl VecStore {
 
 /// Hashtable consisting of `L` Hash tables.
 pub trait HashTables {
+    /// Store `d` once in the shared `VecStore` and register that single index in every one
+    /// of its `L` hash tables, `hashes[hash_table]` being the bucket for `hash_table`.
+    ///
+    /// Takes all `L` hashes in one call, rather than one `hash_table` at a time, so the
+    /// point gets exactly one `VecStore` index shared across every bucket it lands in —
+    /// calling this once per table would push a fresh, unrelated index each time, which
+    /// defeats the reverse index's per-point bucket list and lets `query` double-count a
+    /// point that hashes into matching buckets across several tables.
+    ///
     /// # Arguments
     ///
-    /// * `hash` - hashed vector.
+    /// * `hashes` - hashed vector, one per hash table, indexed by `hash_table`.
     /// * `d` - Vector to store in the buckets.
-    /// * `hash_table` - Number of the hash_table to store the vector. Ranging from 0 to L.
-    fn put(&mut self, hash: Hash, d: DataPoint, hash_table: usize) -> Result<(), HashTableError>;
+    fn put(&mut self, hashes: &[Hash], d: DataPoint) -> Result<(), HashTableError>;
 
     fn delete(
         &mut self,
         hash: Hash,
-        d: &DataPointSlice,
+        d: DataPointSlice<'_>,
         hash_table: usize,
     ) -> Result<(), HashTableError>;
 
+    /// Remove a point by its `VecStore` index directly, without scanning for it. Removes
+    /// the index from every bucket the reverse index says contains it and frees its slot.
+    fn delete_by_idx(&mut self, idx: u32) -> Result<(), HashTableError>;
+
+    /// Keep only the points for which `f` returns `true`, dropping the rest from every
+    /// bucket and reclaiming their `VecStore` slots in one pass.
+    fn retain(&mut self, f: &dyn Fn(&DataPoint) -> bool);
+
     /// Query the whole bucket
     fn query_bucket(&self, hash: &Hash, hash_table: usize) -> Result<&Bucket, HashTableError>;
 
-    /// Query the most similar
-    fn query(&self, distance_fn: &dyn Fn(DataPoint) -> f32) -> Result<DataPoint, HashTableError>;
-
-    fn idx_to_datapoint(&self, idx: u32) -> &DataPoint;
+    /// Look up the point stored at `idx`. Returns an owned `DataPoint` rather than a
+    /// reference so an implementation that doesn't keep points resident in memory (see
+    /// `MmapTable`) can decode it on demand instead of promising a long-lived borrow into
+    /// a heap-resident store.
+    fn idx_to_datapoint(&self, idx: u32) -> DataPoint;
 
     fn increase_storage(&mut self, size: usize);
+
+    /// Number of distinct hashes (non-empty buckets) in `hash_table`'s bucket map relative
+    /// to the map's reserved capacity. A value near 1.0 means the map is close to full.
+    /// It counts buckets, not points, so it does not reveal a single bucket that holds
+    /// most of the points.
+    fn load_factor(&self, hash_table: usize) -> f32;
+
+    /// Approximate `k`-nearest-neighbor search.
+    ///
+    /// `hashes` holds the already-computed hash of the query vector for each of the `L`
+    /// hash tables. Candidates are gathered by unioning the matching bucket from every
+    /// table into a dedup set (so a point hashing into several matching buckets is only
+    /// scored once), then the `k` closest by `distance_fn` are kept using a bounded
+    /// max-heap so memory stays `O(k)` instead of sorting every candidate.
+    fn query(
+        &self,
+        hashes: &[Hash],
+        distance_fn: &dyn for<'a> Fn(DataPointSlice<'a>) -> f32,
+        k: usize,
+    ) -> Result<Vec<(u32, f32)>, HashTableError> {
+        let mut candidates: HashSet<u32> = HashSet::default();
+        for (hash_table, hash) in hashes.iter().enumerate() {
+            if let Ok(bucket) = self.query_bucket(hash, hash_table) {
+                candidates.extend(bucket.iter().copied());
+            }
+        }
+        if candidates.is_empty() {
+            return Err(HashTableError::NotFound);
+        }
+
+        let mut heap: std::collections::BinaryHeap<ScoredIdx> =
+            std::collections::BinaryHeap::with_capacity(k + 1);
+        for idx in candidates {
+            let dist = distance_fn(self.idx_to_datapoint(idx).as_slice());
+            heap.push(ScoredIdx { dist, idx });
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+
+        let mut out: Vec<(u32, f32)> = heap.into_iter().map(|s| (s.idx, s.dist)).collect();
+        out.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        Ok(out)
+    }
+}
+
+/// Helper for `HashTables::query`'s bounded top-k heap: orders candidates by distance so
+/// the farthest match sits at the top of the max-heap and is the one evicted.
+struct ScoredIdx {
+    dist: f32,
+    idx: u32,
+}
+
+impl PartialEq for ScoredIdx {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist == other.dist
+    }
+}
+
+impl Eq for ScoredIdx {}
+
+impl PartialOrd for ScoredIdx {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScoredIdx {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.dist
+            .partial_cmp(&other.dist)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    }
+}
+
+/// Targets a ~0.9 load factor when pre-sizing a table's bucket maps, so bulk loads don't
+/// rehash repeatedly as they approach `expected_points`.
+struct ResizePolicy {
+    target_load_factor: f32,
+}
+
+impl ResizePolicy {
+    const fn new() -> ResizePolicy {
+        ResizePolicy {
+            target_load_factor: 0.9,
+        }
+    }
+
+    /// Estimate how many distinct hash values `expected_points` will produce for a hash
+    /// key of `hash_key_width` bits (capped by the key space itself), then size slots so
+    /// that count sits at `target_load_factor`, rounded up to the next power of two.
+    fn initial_capacity(&self, expected_points: usize, hash_key_width: u32) -> usize {
+        // Compute the key space in u64 and cap the shift at 63 so this can't panic with
+        // a shift-by-bit-width overflow on a 32-bit `usize` target, then clamp into
+        // `usize` (saturating at `usize::MAX`, which only matters on 32-bit targets
+        // given a wide hash key).
+        let shift = hash_key_width.min(63);
+        let key_space = (1u64 << shift).min(usize::MAX as u64) as usize;
+        let estimated_distinct = expected_points.min(key_space).max(1);
+        let raw = (estimated_distinct as f32 / self.target_load_factor).ceil() as usize;
+        raw.next_power_of_two()
+    }
 }
 
 pub struct MemoryTable {
     hash_tables: Vec<HashMap<Hash, Bucket>>,
     n_hash_tables: usize,
     vec_store: VecStore,
+    /// For each stored index, the `(hash, hash_table)` pairs whose buckets reference it.
+    /// Lets `delete`/`delete_by_idx` remove a point without scanning every bucket.
+    reverse_index: HashMap<u32, ReverseEntries>,
+    /// Set by `with_capacity`; lets `increase_storage` re-derive a load-factor-targeted
+    /// bucket capacity instead of reserving the raw point count.
+    hash_key_width: Option<u32>,
 }
 
 impl MemoryTable {
     pub fn new(n_hash_tables: usize) -> MemoryTable {
-        // TODO: Check the average number of vectors in the buckets.
-        // this way the capacity can be approximated by the number of DataPoints that will
-        // be stored.
         let hash_tables = vec![HashMap::default(); n_hash_tables];
-        let vector_store = VecStore { map: vec![] };
+        let vector_store = VecStore {
+            map: vec![],
+            free: vec![],
+        };
+        MemoryTable {
+            hash_tables,
+            n_hash_tables,
+            vec_store: vector_store,
+            reverse_index: HashMap::default(),
+            hash_key_width: None,
+        }
+    }
+
+    /// Like [`MemoryTable::new`], but pre-sizes every inner bucket map for
+    /// `expected_points`, targeting a ~0.9 load factor given a hash key of
+    /// `hash_key_width` bits. Avoids the repeated rehashing a bulk load into an
+    /// empty table would otherwise trigger.
+    pub fn with_capacity(
+        n_hash_tables: usize,
+        expected_points: usize,
+        hash_key_width: u32,
+    ) -> MemoryTable {
+        let capacity = ResizePolicy::new().initial_capacity(expected_points, hash_key_width);
+        let hash_tables = (0..n_hash_tables)
+            .map(|_| {
+                let mut tbl = HashMap::default();
+                tbl.reserve(capacity);
+                tbl
+            })
+            .collect();
+        let mut vector_store = VecStore {
+            map: vec![],
+            free: vec![],
+        };
+        vector_store.increase_storage(expected_points);
         MemoryTable {
             hash_tables,
             n_hash_tables,
             vec_store: vector_store,
+            reverse_index: HashMap::default(),
+            hash_key_width: Some(hash_key_width),
         }
     }
 }
 
 impl HashTables for MemoryTable {
-    fn put(&mut self, hash: Hash, d: DataPoint, hash_table: usize) -> Result<(), HashTableError> {
-        let tbl = &mut self.hash_tables[hash_table];
-        let bucket = tbl.entry(hash).or_insert_with(|| HashSet::default());
+    fn put(&mut self, hashes: &[Hash], d: DataPoint) -> Result<(), HashTableError> {
         let idx = self.vec_store.push(d);
-        bucket.insert(idx);
+        for (hash_table, hash) in hashes.iter().enumerate() {
+            let tbl = &mut self.hash_tables[hash_table];
+            let bucket = tbl.entry(*hash).or_default();
+            bucket.insert(idx);
+            self.reverse_index
+                .entry(idx)
+                .or_default()
+                .push((*hash, hash_table));
+        }
         Ok(())
     }
 
-    /// Expensive operation we need to do a linear search over all datapoints
     fn delete(
         &mut self,
         hash: Hash,
-        d: &DataPointSlice,
+        d: DataPointSlice<'_>,
         hash_table: usize,
     ) -> Result<(), HashTableError> {
         // First find the data point in the VecStore
@@ -109,18 +409,68 @@ impl HashTables for MemoryTable {
             None => return Ok(()),
             Some(idx) => idx,
         };
-        // Note: data point remains in VecStore as shrinking the vector would mean we need to
-        // re-hash all datapoints.
 
-        // Then remove idx from hash tables
+        // Then remove idx from hash tables, dropping the hash's entry entirely once its
+        // bucket is empty so `load_factor` reflects live occupancy, not every hash ever seen.
         let tbl = &mut self.hash_tables[hash_table];
-        let bucket = tbl.get_mut(&hash);
-        match bucket {
+        let now_empty = match tbl.get_mut(&hash) {
             None => return Err(HashTableError::NotFound),
             Some(bucket) => {
                 bucket.remove(&idx);
-                Ok(())
+                bucket.is_empty()
             }
+        };
+        if now_empty {
+            tbl.remove(&hash);
+        }
+
+        // Drop the (hash, hash_table) entry from the reverse index; once nothing
+        // references idx anymore its VecStore slot can be reclaimed.
+        if let Some(entries) = self.reverse_index.get_mut(&idx) {
+            entries.retain(|(h, t)| !(*h == hash && *t == hash_table));
+            if entries.is_empty() {
+                self.reverse_index.remove(&idx);
+                self.vec_store.remove(idx);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_by_idx(&mut self, idx: u32) -> Result<(), HashTableError> {
+        let entries = match self.reverse_index.remove(&idx) {
+            None => return Err(HashTableError::NotFound),
+            Some(entries) => entries,
+        };
+        for (hash, hash_table) in entries {
+            let tbl = &mut self.hash_tables[hash_table];
+            let now_empty = match tbl.get_mut(&hash) {
+                None => false,
+                Some(bucket) => {
+                    bucket.remove(&idx);
+                    bucket.is_empty()
+                }
+            };
+            if now_empty {
+                tbl.remove(&hash);
+            }
+        }
+        self.vec_store.remove(idx);
+        Ok(())
+    }
+
+    fn retain(&mut self, f: &dyn Fn(&DataPoint) -> bool) {
+        let to_remove: Vec<u32> = self
+            .vec_store
+            .map
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, d)| match d {
+                Some(d) if !f(d) => Some(idx as u32),
+                _ => None,
+            })
+            .collect();
+        for idx in to_remove {
+            let _ = self.delete_by_idx(idx);
         }
     }
 
@@ -133,17 +483,30 @@ impl HashTables for MemoryTable {
         }
     }
 
-    /// Query the most similar
-    fn query(&self, distance_fn: &dyn Fn(DataPoint) -> f32) -> Result<DataPoint, HashTableError> {
-        Err(HashTableError::Failed)
-    }
-
-    fn idx_to_datapoint(&self, idx: u32) -> &DataPoint {
-        self.vec_store.get(idx)
+    fn idx_to_datapoint(&self, idx: u32) -> DataPoint {
+        self.vec_store.get(idx).clone()
     }
 
     fn increase_storage(&mut self, size: usize) {
         self.vec_store.increase_storage(size);
+        let capacity = match self.hash_key_width {
+            Some(width) => ResizePolicy::new().initial_capacity(size, width),
+            None => size,
+        };
+        for tbl in self.hash_tables.iter_mut() {
+            // `reserve` takes a count of entries *beyond* the current length, not an
+            // absolute target, so only reserve when short of `capacity` and ask for the
+            // difference from `len()` instead of passing `capacity` straight through.
+            if tbl.capacity() < capacity {
+                let diff = capacity - tbl.len();
+                tbl.reserve(diff);
+            }
+        }
+    }
+
+    fn load_factor(&self, hash_table: usize) -> f32 {
+        let tbl = &self.hash_tables[hash_table];
+        tbl.len() as f32 / tbl.capacity().max(1) as f32
     }
 }
 
@@ -156,3 +519,900 @@ impl std::fmt::Debug for MemoryTable {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_prunes_the_bucket_once_it_is_empty() {
+        let mut table = MemoryTable::new(1);
+        let hash: Hash = 1;
+        table
+            .put(&[hash], DataPoint::Dense(vec![1.0, 2.0]))
+            .unwrap();
+        assert!(table.query_bucket(&hash, 0).is_ok());
+
+        table
+            .delete(hash, DataPointSlice::Dense(&[1.0, 2.0]), 0)
+            .unwrap();
+
+        // The bucket's only entry is gone, so its `Hash` key should be gone too, not left
+        // behind as an empty bucket that keeps counting against `load_factor`.
+        assert!(matches!(
+            table.query_bucket(&hash, 0),
+            Err(HashTableError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn delete_by_idx_prunes_the_bucket_once_it_is_empty() {
+        let mut table = MemoryTable::new(1);
+        let hash: Hash = 7;
+        let idx_marker = DataPoint::Dense(vec![3.0]);
+        table.put(&[hash], idx_marker).unwrap();
+        table.delete_by_idx(0).unwrap();
+
+        assert!(matches!(
+            table.query_bucket(&hash, 0),
+            Err(HashTableError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn put_shares_one_idx_across_all_hash_tables() {
+        // A point inserted with `hashes.len() == n_hash_tables` should land in every
+        // table's bucket under the *same* VecStore index, not a fresh one per table.
+        let mut table = MemoryTable::new(3);
+        let hashes: [Hash; 3] = [1, 2, 3];
+        table.put(&hashes, DataPoint::Dense(vec![1.0, 2.0])).unwrap();
+
+        for (hash_table, hash) in hashes.iter().enumerate() {
+            let bucket = table.query_bucket(hash, hash_table).unwrap();
+            assert_eq!(bucket.iter().copied().collect::<Vec<_>>(), vec![0]);
+        }
+        assert_eq!(table.reverse_index[&0].len(), 3);
+    }
+
+    #[test]
+    fn query_does_not_double_count_a_point_shared_across_tables() {
+        // The same point hashes into a matching bucket in both tables, so the union-dedup
+        // in `query` must score it once, not twice.
+        let mut table = MemoryTable::new(2);
+        let hash: Hash = 1;
+        table
+            .put(&[hash, hash], DataPoint::Dense(vec![0.0]))
+            .unwrap();
+
+        let results = table
+            .query(
+                &[hash, hash],
+                &|d| match d {
+                    DataPointSlice::Dense(v) => v[0].abs(),
+                    DataPointSlice::Sparse(_) => f32::MAX,
+                },
+                5,
+            )
+            .unwrap();
+
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn query_returns_the_k_closest_points_by_distance() {
+        let mut table = MemoryTable::new(1);
+        let hash: Hash = 1;
+        for (idx, x) in [10.0f32, 1.0, 5.0, 2.0, 8.0].into_iter().enumerate() {
+            table
+                .put(&[hash], DataPoint::Dense(vec![x]))
+                .unwrap_or_else(|_| panic!("put {} failed", idx));
+        }
+
+        let target = 0.0f32;
+        let results = table
+            .query(
+                &[hash],
+                &|d| match d {
+                    DataPointSlice::Dense(v) => (v[0] - target).abs(),
+                    DataPointSlice::Sparse(_) => f32::MAX,
+                },
+                2,
+            )
+            .unwrap();
+
+        assert_eq!(results.len(), 2);
+        // The two points nearest `target` are 1.0 (idx 1) and 2.0 (idx 3), closest first.
+        let closest: Vec<u32> = results.iter().map(|(idx, _)| *idx).collect();
+        assert_eq!(closest, vec![1, 3]);
+    }
+
+    #[test]
+    fn query_reports_not_found_when_no_bucket_matches() {
+        let table = MemoryTable::new(1);
+        let hash: Hash = 42;
+        let result = table.query(&[hash], &|_| 0.0, 1);
+        assert!(matches!(result, Err(HashTableError::NotFound)));
+    }
+
+    #[test]
+    fn sparse_data_point_new_sorts_by_index() {
+        let p = SparseDataPoint::new(vec![3, 1, 2], vec![30.0, 10.0, 20.0], 4);
+        assert_eq!(p.indices(), &[1, 2, 3]);
+        assert_eq!(p.values(), &[10.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn sparse_data_point_dot_skips_indices_present_in_only_one_side() {
+        let a = SparseDataPoint::new(vec![0, 2, 5], vec![1.0, 2.0, 3.0], 8);
+        let b = SparseDataPoint::new(vec![2, 3, 5], vec![4.0, 5.0, 6.0], 8);
+        // Shared indices are 2 (2.0*4.0) and 5 (3.0*6.0); index 0 and 3 contribute nothing.
+        assert_eq!(a.dot(&b), 2.0 * 4.0 + 3.0 * 6.0);
+    }
+
+    #[test]
+    fn data_point_eq_slice_never_matches_across_dense_and_sparse() {
+        let dense = DataPoint::Dense(vec![1.0, 0.0]);
+        let sparse = DataPoint::Sparse(SparseDataPoint::new(vec![0], vec![1.0], 2));
+        assert!(!data_point_eq_slice(&dense, sparse.as_slice()));
+        assert!(!data_point_eq_slice(&sparse, dense.as_slice()));
+    }
+
+    #[test]
+    fn data_point_eq_slice_matches_equal_sparse_points_regardless_of_input_order() {
+        let a = DataPoint::Sparse(SparseDataPoint::new(vec![3, 1], vec![30.0, 10.0], 4));
+        let b = SparseDataPoint::new(vec![1, 3], vec![10.0, 30.0], 4);
+        assert!(data_point_eq_slice(&a, DataPointSlice::Sparse(&b)));
+    }
+
+    #[test]
+    fn initial_capacity_targets_the_configured_load_factor() {
+        let policy = ResizePolicy::new();
+        let capacity = policy.initial_capacity(900, 32);
+        // 900 points at a 0.9 load factor need >= 1000 slots, rounded up to a power of two.
+        assert_eq!(capacity, 1024);
+    }
+
+    #[test]
+    fn initial_capacity_is_bounded_by_the_hash_key_space() {
+        let policy = ResizePolicy::new();
+        // A 2-bit hash key can only ever produce 4 distinct values, however many points
+        // are expected, so capacity shouldn't blow up to fit `expected_points`.
+        let capacity = policy.initial_capacity(1_000_000, 2);
+        assert_eq!(capacity, policy.initial_capacity(4, 2));
+    }
+
+    #[test]
+    fn initial_capacity_does_not_overflow_on_a_full_width_hash_key() {
+        let policy = ResizePolicy::new();
+        // `hash_key_width` can be as wide as the hash type itself (e.g. 32 bits for
+        // `u32`), which must not overflow the key-space shift even on a 32-bit target.
+        let capacity = policy.initial_capacity(1_000_000, 32);
+        assert_eq!(capacity, policy.initial_capacity(1_000_000, 64));
+    }
+
+    #[test]
+    fn with_capacity_presizes_every_bucket_map() {
+        let table = MemoryTable::with_capacity(3, 900, 32);
+        for i in 0..3 {
+            assert!(table.hash_tables[i].capacity() >= 900);
+        }
+    }
+
+    #[test]
+    fn increase_storage_reserves_the_difference_not_the_absolute_target() {
+        let mut table = MemoryTable::with_capacity(1, 16, 32);
+        let capacity_after_with_capacity = table.hash_tables[0].capacity();
+
+        // Calling `increase_storage` again with a smaller size must not shrink or otherwise
+        // disturb the existing reservation.
+        table.increase_storage(4);
+        assert!(table.hash_tables[0].capacity() >= capacity_after_with_capacity);
+
+        table.increase_storage(10_000);
+        assert!(table.hash_tables[0].capacity() > capacity_after_with_capacity);
+    }
+}
+
+mod mmap_table {
+    //! Disk-backed `HashTables` implementation. Points live in a slot-addressed region of
+    //! the mapped file that `put`/`delete` write and zero directly — no whole-index
+    //! encode/decode on the hot path — so an index can exceed the size of RAM and be
+    //! reopened across process runs instead of being rebuilt from scratch every time.
+    use super::*;
+    use memmap2::{MmapMut, MmapOptions};
+    use std::fs::{File, OpenOptions};
+    use std::path::Path;
+
+    const MAGIC: &[u8; 7] = b"LSHRSDB";
+    const VERSION: u8 = 2;
+    const HEADER_SIZE: usize = 7 + 1 + 4 + 4 + 8 + 8 + 8 + 8 + 8;
+    /// Directory entry: `(offset: u64, len: u64)` into the data region. `len == 0` marks
+    /// a free slot.
+    const DIR_ENTRY_SIZE: usize = 16;
+
+    /// Grow the bucket region once occupancy crosses this fraction of its slots.
+    const GROW_LOAD_FACTOR: f32 = 0.9;
+    /// Compact and shrink the bucket region once occupancy drops below this fraction.
+    const SHRINK_LOAD_FACTOR: f32 = 0.35;
+
+    /// Fixed-size preamble written at offset 0 of the backing file. The four region sizes
+    /// let `open()` recompute every offset (directory, data, metadata) without reading
+    /// anything beyond the header, and let it bounds-check the file before trusting it.
+    struct FileHeader {
+        version: u8,
+        n_hash_tables: u32,
+        hash_key_width: u32,
+        slot_capacity: u64,
+        len_slots: u64,
+        data_len: u64,
+        data_capacity: u64,
+        meta_len: u64,
+    }
+
+    impl FileHeader {
+        fn encode(&self, buf: &mut [u8]) {
+            buf[0..7].copy_from_slice(MAGIC);
+            buf[7] = self.version;
+            buf[8..12].copy_from_slice(&self.n_hash_tables.to_le_bytes());
+            buf[12..16].copy_from_slice(&self.hash_key_width.to_le_bytes());
+            buf[16..24].copy_from_slice(&self.slot_capacity.to_le_bytes());
+            buf[24..32].copy_from_slice(&self.len_slots.to_le_bytes());
+            buf[32..40].copy_from_slice(&self.data_len.to_le_bytes());
+            buf[40..48].copy_from_slice(&self.data_capacity.to_le_bytes());
+            buf[48..56].copy_from_slice(&self.meta_len.to_le_bytes());
+        }
+
+        fn decode(buf: &[u8]) -> Result<FileHeader, HashTableError> {
+            if &buf[0..7] != MAGIC {
+                return Err(HashTableError::WrongMagic);
+            }
+            let version = buf[7];
+            if version != VERSION {
+                return Err(HashTableError::UnsupportedVersion);
+            }
+            Ok(FileHeader {
+                version,
+                n_hash_tables: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+                hash_key_width: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+                slot_capacity: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+                len_slots: u64::from_le_bytes(buf[24..32].try_into().unwrap()),
+                data_len: u64::from_le_bytes(buf[32..40].try_into().unwrap()),
+                data_capacity: u64::from_le_bytes(buf[40..48].try_into().unwrap()),
+                meta_len: u64::from_le_bytes(buf[48..56].try_into().unwrap()),
+            })
+        }
+    }
+
+    /// One on-disk hash table: buckets keyed by the LSH hash. Unlike the earlier revision,
+    /// there is no separate `capacity` bookkeeping field — `load_factor`/`maybe_resize`
+    /// read `buckets.capacity()` directly, the same way `MemoryTable::load_factor` does,
+    /// so the two can never drift apart.
+    #[derive(Serialize, Deserialize)]
+    struct BucketRegion {
+        buckets: HashMap<Hash, Bucket>,
+    }
+
+    impl BucketRegion {
+        fn with_capacity(capacity: usize) -> BucketRegion {
+            let mut buckets = HashMap::default();
+            buckets.reserve(capacity.max(1));
+            BucketRegion { buckets }
+        }
+
+        fn load_factor(&self) -> f32 {
+            self.buckets.len() as f32 / self.buckets.capacity().max(1) as f32
+        }
+
+        /// Grow `buckets`' real reservation once occupancy crosses `GROW_LOAD_FACTOR`, or
+        /// shrink and compact it once occupancy drops below `SHRINK_LOAD_FACTOR`.
+        fn maybe_resize(&mut self) {
+            let lf = self.load_factor();
+            if lf > GROW_LOAD_FACTOR {
+                let target = (self.buckets.capacity().max(1) * 2).next_power_of_two();
+                if self.buckets.capacity() < target {
+                    self.buckets.reserve(target - self.buckets.capacity());
+                }
+            } else if lf < SHRINK_LOAD_FACTOR && self.buckets.capacity() > 1 {
+                self.buckets.shrink_to_fit();
+            }
+        }
+    }
+
+    /// Disk-backed, memory-mapped `HashTables` implementation.
+    ///
+    /// The backing file starts with a [`FileHeader`], followed by three regions computed
+    /// from it: a fixed-size point *directory* (one `(offset, len)` entry per `VecStore`
+    /// slot), an append-only point *data* region the directory's offsets point into, and a
+    /// `bincode`-encoded *metadata* blob (the free-slot list and one [`BucketRegion`] per
+    /// hash table) at the tail of the file.
+    ///
+    /// `put`/`delete` write straight into the directory and data region — a new point is
+    /// appended and its one directory entry is written; a removed point just has its
+    /// directory entry zeroed — so the point data a mutation writes is only that one
+    /// point's. `delete` locates its point by decoding stored points one by one until one
+    /// matches; `delete_by_idx` skips that scan. Only the metadata blob (bucket contents,
+    /// which are small `u32` indices, not point data) is re-encoded and rewritten on every
+    /// mutation, which is cheap relative to point data and sits at the end of the file, so
+    /// growing it never needs to move anything else. `idx_to_datapoint` decodes straight
+    /// from the mapped bytes on every call instead of keeping points resident in memory, so
+    /// an index's RAM footprint stays independent of how many points it holds.
+    ///
+    /// The directory and data region only grow by doubling, and growth is the one point at
+    /// which the whole index is touched: live points are decoded and re-appended into a
+    /// larger, defragmented data region (reclaiming the holes past deletes leave behind),
+    /// the same amortized-reallocation trade `Vec`/`HashMap` already make elsewhere in this
+    /// file. Bucket regions are grown once occupancy exceeds ~0.9 and compacted once it
+    /// drops below ~0.35, so repeated inserts/deletes don't leave them permanently
+    /// oversized or full of dead slots.
+    pub struct MmapTable {
+        file: File,
+        mmap: MmapMut,
+        hash_key_width: u32,
+        /// Number of directory slots reserved.
+        slot_capacity: u64,
+        /// Number of directory slots ever handed out by `push` (some may be free).
+        len_slots: u64,
+        /// Bytes of the data region in use, including holes left by deleted points.
+        data_len: u64,
+        /// Bytes reserved for the data region; growing past this moves the metadata blob.
+        data_capacity: u64,
+        /// Bytes the encoded metadata blob occupies at the tail of the file. Tracked
+        /// explicitly rather than derived from the file length, since the file can be
+        /// longer than `meta_start() + meta_len` right after the blob shrinks.
+        meta_len: u64,
+        /// Slots freed by a delete, reused by the next `push` before growing `len_slots`.
+        free: Vec<u32>,
+        regions: Vec<BucketRegion>,
+        /// Derived from `regions` on `open()`/construction; not itself persisted.
+        reverse_index: HashMap<u32, ReverseEntries>,
+    }
+
+    impl MmapTable {
+        const INITIAL_SLOT_CAPACITY: u64 = 16;
+        const INITIAL_DATA_CAPACITY: u64 = 1024;
+
+        fn dir_start() -> usize {
+            HEADER_SIZE
+        }
+
+        fn data_start(&self) -> usize {
+            Self::dir_start() + self.slot_capacity as usize * DIR_ENTRY_SIZE
+        }
+
+        fn meta_start(&self) -> usize {
+            self.data_start() + self.data_capacity as usize
+        }
+
+        /// Create a new, empty index backed by `path`.
+        pub fn new(
+            path: &Path,
+            n_hash_tables: usize,
+            hash_key_width: u32,
+        ) -> Result<MmapTable, HashTableError> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+
+            let mut table = MmapTable {
+                file,
+                mmap: MmapMut::map_anon(HEADER_SIZE)?,
+                hash_key_width,
+                slot_capacity: 0,
+                len_slots: 0,
+                data_len: 0,
+                data_capacity: 0,
+                meta_len: 0,
+                free: vec![],
+                regions: (0..n_hash_tables)
+                    .map(|_| BucketRegion::with_capacity(1))
+                    .collect(),
+                reverse_index: HashMap::default(),
+            };
+            table.layout(Self::INITIAL_SLOT_CAPACITY, Self::INITIAL_DATA_CAPACITY)?;
+            Ok(table)
+        }
+
+        /// Reopen a previously persisted index, validating the header, recomputing every
+        /// region's offsets from it, and decoding only the (small) metadata blob — point
+        /// data is left on disk and decoded lazily by `idx_to_datapoint`.
+        pub fn open(path: &Path) -> Result<MmapTable, HashTableError> {
+            let file = OpenOptions::new().read(true).write(true).open(path)?;
+            let mmap = unsafe { MmapOptions::new().map_mut(&file)? };
+            if mmap.len() < HEADER_SIZE {
+                return Err(HashTableError::Truncated);
+            }
+            let header = FileHeader::decode(&mmap[..HEADER_SIZE])?;
+
+            let data_start = HEADER_SIZE + header.slot_capacity as usize * DIR_ENTRY_SIZE;
+            let meta_start = data_start + header.data_capacity as usize;
+            let meta_end = meta_start + header.meta_len as usize;
+            if meta_end > mmap.len() {
+                return Err(HashTableError::Truncated);
+            }
+
+            let (free, regions): (Vec<u32>, Vec<BucketRegion>) = if header.meta_len == 0 {
+                (vec![], (0..header.n_hash_tables as usize).map(|_| BucketRegion::with_capacity(1)).collect())
+            } else {
+                bincode::deserialize(&mmap[meta_start..meta_end])
+                    .map_err(|_| HashTableError::Failed)?
+            };
+
+            let mut reverse_index: HashMap<u32, ReverseEntries> = HashMap::default();
+            for (hash_table, region) in regions.iter().enumerate() {
+                for (hash, bucket) in region.buckets.iter() {
+                    for &idx in bucket.iter() {
+                        reverse_index
+                            .entry(idx)
+                            .or_default()
+                            .push((*hash, hash_table));
+                    }
+                }
+            }
+
+            Ok(MmapTable {
+                file,
+                mmap,
+                hash_key_width: header.hash_key_width,
+                slot_capacity: header.slot_capacity,
+                len_slots: header.len_slots,
+                data_len: header.data_len,
+                data_capacity: header.data_capacity,
+                meta_len: header.meta_len,
+                free,
+                regions,
+                reverse_index,
+            })
+        }
+
+        fn write_header(&mut self) -> Result<(), HashTableError> {
+            let header = FileHeader {
+                version: VERSION,
+                n_hash_tables: self.regions.len() as u32,
+                hash_key_width: self.hash_key_width,
+                slot_capacity: self.slot_capacity,
+                len_slots: self.len_slots,
+                data_len: self.data_len,
+                data_capacity: self.data_capacity,
+                meta_len: self.meta_len,
+            };
+            header.encode(&mut self.mmap[..HEADER_SIZE]);
+            self.mmap.flush().map_err(|_| HashTableError::Failed)
+        }
+
+        fn dir_entry(&self, idx: u32) -> (u64, u64) {
+            let off = Self::dir_start() + idx as usize * DIR_ENTRY_SIZE;
+            let offset = u64::from_le_bytes(self.mmap[off..off + 8].try_into().unwrap());
+            let len = u64::from_le_bytes(self.mmap[off + 8..off + 16].try_into().unwrap());
+            (offset, len)
+        }
+
+        fn write_dir_entry(&mut self, idx: u32, offset: u64, len: u64) {
+            let off = Self::dir_start() + idx as usize * DIR_ENTRY_SIZE;
+            self.mmap[off..off + 8].copy_from_slice(&offset.to_le_bytes());
+            self.mmap[off + 8..off + 16].copy_from_slice(&len.to_le_bytes());
+        }
+
+        /// Decode the point at `idx` directly from the mapped data region.
+        fn read_point(&self, idx: u32) -> DataPoint {
+            let (offset, len) = self.dir_entry(idx);
+            let start = self.data_start() + offset as usize;
+            bincode::deserialize(&self.mmap[start..start + len as usize])
+                .expect("corrupt point record in MmapTable data region")
+        }
+
+        /// Append `d`'s encoding to the data region and record its directory entry,
+        /// reusing a freed slot if one is available. Reflows the backing regions first if
+        /// there isn't room, so this is O(1) amortized, not O(index size).
+        fn push_point(&mut self, d: &DataPoint) -> Result<u32, HashTableError> {
+            let bytes = bincode::serialize(d).map_err(|_| HashTableError::Failed)?;
+            let needs_slot = self.free.is_empty() && self.len_slots == self.slot_capacity;
+            let needs_data = self.data_len + bytes.len() as u64 > self.data_capacity;
+            if needs_slot || needs_data {
+                let new_slot_capacity = if needs_slot {
+                    (self.slot_capacity.max(1) * 2).max(Self::INITIAL_SLOT_CAPACITY)
+                } else {
+                    self.slot_capacity
+                };
+                let new_data_capacity = if needs_data {
+                    (self.data_len + bytes.len() as u64)
+                        .max(self.data_capacity.max(1))
+                        .next_power_of_two()
+                } else {
+                    self.data_capacity
+                };
+                self.layout(new_slot_capacity, new_data_capacity)?;
+            }
+
+            let idx = match self.free.pop() {
+                Some(idx) => idx,
+                None => {
+                    let idx = self.len_slots as u32;
+                    self.len_slots += 1;
+                    idx
+                }
+            };
+            let offset = self.data_len;
+            let start = self.data_start() + offset as usize;
+            self.mmap[start..start + bytes.len()].copy_from_slice(&bytes);
+            self.data_len += bytes.len() as u64;
+            self.write_dir_entry(idx, offset, bytes.len() as u64);
+            Ok(idx)
+        }
+
+        /// Mark `idx`'s directory entry free. The data bytes become an unreachable hole,
+        /// reclaimed the next time the data region reflows.
+        fn remove_point(&mut self, idx: u32) {
+            self.write_dir_entry(idx, 0, 0);
+            self.free.push(idx);
+        }
+
+        /// The guts of `delete_by_idx`, minus the metadata sync: removes `idx` from every
+        /// bucket it was ever inserted into and frees its slot, but leaves `sync_metadata`
+        /// to the caller. Lets `retain` remove many points in one pass and sync once,
+        /// instead of rewriting the free list and bucket regions after every point.
+        fn delete_by_idx_unsynced(&mut self, idx: u32) -> Result<(), HashTableError> {
+            let entries = match self.reverse_index.remove(&idx) {
+                None => return Err(HashTableError::NotFound),
+                Some(entries) => entries,
+            };
+            for (hash, hash_table) in entries {
+                let region = &mut self.regions[hash_table];
+                let now_empty = match region.buckets.get_mut(&hash) {
+                    None => false,
+                    Some(bucket) => {
+                        bucket.remove(&idx);
+                        bucket.is_empty()
+                    }
+                };
+                if now_empty {
+                    region.buckets.remove(&hash);
+                }
+                region.maybe_resize();
+            }
+            self.remove_point(idx);
+            Ok(())
+        }
+
+        /// (Re)allocate the file so the directory holds `new_slot_capacity` entries and the
+        /// data region holds `new_data_capacity` bytes, defragmenting live points into the
+        /// new data region in the process (their `idx`s are unchanged, so buckets and the
+        /// reverse index stay valid). Used both for initial layout and for growth.
+        fn layout(
+            &mut self,
+            new_slot_capacity: u64,
+            new_data_capacity: u64,
+        ) -> Result<(), HashTableError> {
+            let live: Vec<(u32, Vec<u8>)> = (0..self.len_slots as u32)
+                .filter_map(|idx| {
+                    let (offset, len) = self.dir_entry(idx);
+                    if len == 0 {
+                        return None;
+                    }
+                    let start = self.data_start() + offset as usize;
+                    Some((idx, self.mmap[start..start + len as usize].to_vec()))
+                })
+                .collect();
+            let meta_bytes = bincode::serialize(&(&self.free, &self.regions))
+                .map_err(|_| HashTableError::Failed)?;
+
+            let new_data_start = HEADER_SIZE + new_slot_capacity as usize * DIR_ENTRY_SIZE;
+            let new_meta_start = new_data_start + new_data_capacity as usize;
+            let new_file_len = new_meta_start + meta_bytes.len();
+
+            self.file.set_len(new_file_len as u64)?;
+            self.mmap = unsafe { MmapOptions::new().map_mut(&self.file)? };
+
+            self.mmap[HEADER_SIZE..new_data_start].fill(0);
+            let mut cursor = 0u64;
+            for (idx, bytes) in &live {
+                let start = new_data_start + cursor as usize;
+                self.mmap[start..start + bytes.len()].copy_from_slice(bytes);
+                let dir_off = HEADER_SIZE + *idx as usize * DIR_ENTRY_SIZE;
+                self.mmap[dir_off..dir_off + 8].copy_from_slice(&cursor.to_le_bytes());
+                self.mmap[dir_off + 8..dir_off + 16]
+                    .copy_from_slice(&(bytes.len() as u64).to_le_bytes());
+                cursor += bytes.len() as u64;
+            }
+            self.mmap[new_meta_start..new_meta_start + meta_bytes.len()]
+                .copy_from_slice(&meta_bytes);
+
+            self.slot_capacity = new_slot_capacity;
+            self.data_len = cursor;
+            self.data_capacity = new_data_capacity;
+            self.meta_len = meta_bytes.len() as u64;
+            self.write_header()
+        }
+
+        /// Re-encode the (small) free-list + bucket-region metadata and write it at the
+        /// tail of the file, growing the file if it no longer fits. Never touches point
+        /// data, so its cost is proportional to the number of buckets, not the index size.
+        fn sync_metadata(&mut self) -> Result<(), HashTableError> {
+            let meta_bytes = bincode::serialize(&(&self.free, &self.regions))
+                .map_err(|_| HashTableError::Failed)?;
+            let meta_start = self.meta_start();
+            let required_len = meta_start + meta_bytes.len();
+            if self.mmap.len() < required_len {
+                self.file.set_len(required_len as u64)?;
+                self.mmap = unsafe { MmapOptions::new().map_mut(&self.file)? };
+            }
+            self.mmap[meta_start..meta_start + meta_bytes.len()].copy_from_slice(&meta_bytes);
+            self.meta_len = meta_bytes.len() as u64;
+            self.write_header()
+        }
+    }
+
+    impl HashTables for MmapTable {
+        fn put(&mut self, hashes: &[Hash], d: DataPoint) -> Result<(), HashTableError> {
+            let idx = self.push_point(&d)?;
+            for (hash_table, hash) in hashes.iter().enumerate() {
+                let region = &mut self.regions[hash_table];
+                region.buckets.entry(*hash).or_default().insert(idx);
+                region.maybe_resize();
+                self.reverse_index
+                    .entry(idx)
+                    .or_default()
+                    .push((*hash, hash_table));
+            }
+            self.sync_metadata()
+        }
+
+        fn delete(
+            &mut self,
+            hash: Hash,
+            d: DataPointSlice<'_>,
+            hash_table: usize,
+        ) -> Result<(), HashTableError> {
+            let idx = match (0..self.len_slots as u32).find(|&idx| {
+                let (_, len) = self.dir_entry(idx);
+                len > 0 && data_point_eq_slice(&self.read_point(idx), d)
+            }) {
+                None => return Ok(()),
+                Some(idx) => idx,
+            };
+            // Drop the hash's entry entirely once its bucket is empty, the same as
+            // `MemoryTable::delete`, so `maybe_resize`'s load factor reflects live
+            // occupancy instead of every hash ever seen.
+            let region = &mut self.regions[hash_table];
+            let now_empty = match region.buckets.get_mut(&hash) {
+                None => return Err(HashTableError::NotFound),
+                Some(bucket) => {
+                    bucket.remove(&idx);
+                    bucket.is_empty()
+                }
+            };
+            if now_empty {
+                region.buckets.remove(&hash);
+            }
+            region.maybe_resize();
+            if let Some(entries) = self.reverse_index.get_mut(&idx) {
+                entries.retain(|(h, t)| !(*h == hash && *t == hash_table));
+                if entries.is_empty() {
+                    self.reverse_index.remove(&idx);
+                    self.remove_point(idx);
+                }
+            }
+            self.sync_metadata()
+        }
+
+        fn delete_by_idx(&mut self, idx: u32) -> Result<(), HashTableError> {
+            self.delete_by_idx_unsynced(idx)?;
+            self.sync_metadata()
+        }
+
+        fn retain(&mut self, f: &dyn Fn(&DataPoint) -> bool) {
+            let to_remove: Vec<u32> = (0..self.len_slots as u32)
+                .filter(|&idx| {
+                    let (_, len) = self.dir_entry(idx);
+                    len > 0 && !f(&self.read_point(idx))
+                })
+                .collect();
+            if to_remove.is_empty() {
+                return;
+            }
+            for idx in to_remove {
+                let _ = self.delete_by_idx_unsynced(idx);
+            }
+            let _ = self.sync_metadata();
+        }
+
+        fn query_bucket(&self, hash: &Hash, hash_table: usize) -> Result<&Bucket, HashTableError> {
+            self.regions[hash_table]
+                .buckets
+                .get(hash)
+                .ok_or(HashTableError::NotFound)
+        }
+
+        fn idx_to_datapoint(&self, idx: u32) -> DataPoint {
+            self.read_point(idx)
+        }
+
+        fn increase_storage(&mut self, size: usize) {
+            if (size as u64) > self.slot_capacity {
+                let new_slot_capacity = (size as u64).next_power_of_two();
+                self.layout(new_slot_capacity, self.data_capacity)
+                    .expect("failed to grow MmapTable's directory");
+            }
+        }
+
+        fn load_factor(&self, hash_table: usize) -> f32 {
+            self.regions[hash_table].load_factor()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Write;
+
+        fn scratch_path(name: &str) -> std::path::PathBuf {
+            std::env::temp_dir().join(format!("lsh-rs-table-test-{}-{}", std::process::id(), name))
+        }
+
+        #[test]
+        fn open_rejects_file_shorter_than_header() {
+            let path = scratch_path("short-header");
+            std::fs::File::create(&path)
+                .unwrap()
+                .write_all(&[0u8; HEADER_SIZE - 1])
+                .unwrap();
+            match MmapTable::open(&path) {
+                Err(HashTableError::Truncated) => {}
+                Err(other) => panic!("expected Truncated, got {:?}", other),
+                Ok(_) => panic!("expected Truncated, got Ok"),
+            }
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn delete_prunes_the_bucket_once_it_is_empty() {
+            let path = scratch_path("delete-prunes-bucket");
+            let mut table = MmapTable::new(&path, 1, 8).unwrap();
+            let hash: Hash = 1;
+            table
+                .put(&[hash], DataPoint::Dense(vec![1.0, 2.0]))
+                .unwrap();
+            assert!(table.query_bucket(&hash, 0).is_ok());
+
+            table
+                .delete(hash, DataPointSlice::Dense(&[1.0, 2.0]), 0)
+                .unwrap();
+
+            assert!(matches!(
+                table.query_bucket(&hash, 0),
+                Err(HashTableError::NotFound)
+            ));
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn maybe_resize_shrinks_the_real_allocation_not_just_a_counter() {
+            let mut region = BucketRegion::with_capacity(1);
+            for i in 0..64u32 {
+                region.buckets.insert(i as Hash, Bucket::default());
+                region.maybe_resize();
+            }
+            assert!(region.buckets.capacity() >= 64);
+            assert!(region.load_factor() <= GROW_LOAD_FACTOR);
+
+            for i in 0..60u32 {
+                region.buckets.remove(&(i as Hash));
+                region.maybe_resize();
+            }
+            // `shrink_to_fit` must actually have run against the real `HashMap`, not just
+            // updated a `capacity` field that no longer tracks the allocation.
+            assert!(region.buckets.capacity() < 64);
+        }
+
+        #[test]
+        fn open_rejects_meta_len_past_end_of_file() {
+            let path = scratch_path("bad-meta-len");
+            let mut buf = vec![0u8; HEADER_SIZE];
+            let header = FileHeader {
+                version: VERSION,
+                n_hash_tables: 1,
+                hash_key_width: 8,
+                slot_capacity: 16,
+                len_slots: 0,
+                data_len: 0,
+                data_capacity: 1024,
+                meta_len: 1_000_000,
+            };
+            header.encode(&mut buf);
+            std::fs::File::create(&path)
+                .unwrap()
+                .write_all(&buf)
+                .unwrap();
+            match MmapTable::open(&path) {
+                Err(HashTableError::Truncated) => {}
+                Err(other) => panic!("expected Truncated, got {:?}", other),
+                Ok(_) => panic!("expected Truncated, got Ok"),
+            }
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn reopen_restores_points_and_buckets() {
+            let path = scratch_path("reopen-restores-points");
+            {
+                let mut table = MmapTable::new(&path, 1, 8).unwrap();
+                table
+                    .put(&[1], DataPoint::Dense(vec![1.0, 2.0]))
+                    .unwrap();
+                table.put(&[1], DataPoint::Dense(vec![3.0, 4.0])).unwrap();
+            }
+
+            let table = MmapTable::open(&path).unwrap();
+            let bucket = table.query_bucket(&1, 0).unwrap().clone();
+            let mut points: Vec<DataPoint> = bucket
+                .iter()
+                .map(|&idx| table.idx_to_datapoint(idx))
+                .collect();
+            points.sort_by(|a, b| match (a, b) {
+                (DataPoint::Dense(a), DataPoint::Dense(b)) => a.partial_cmp(b).unwrap(),
+                _ => std::cmp::Ordering::Equal,
+            });
+            assert!(matches!(&points[0], DataPoint::Dense(v) if v == &[1.0, 2.0]));
+            assert!(matches!(&points[1], DataPoint::Dense(v) if v == &[3.0, 4.0]));
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn put_does_not_rewrite_earlier_points_on_every_call() {
+            // A later put's directory-entry write must not disturb an earlier point's
+            // already-recorded offset/len, the way a whole-body re-encode would risk.
+            let path = scratch_path("put-is-incremental");
+            let mut table = MmapTable::new(&path, 1, 8).unwrap();
+            table.put(&[1], DataPoint::Dense(vec![1.0])).unwrap();
+            let first_entry = table.dir_entry(0);
+
+            for i in 0..32 {
+                table
+                    .put(&[1], DataPoint::Dense(vec![i as f32]))
+                    .unwrap();
+            }
+
+            assert_eq!(table.dir_entry(0), first_entry);
+            assert!(matches!(table.idx_to_datapoint(0), DataPoint::Dense(v) if v == vec![1.0]));
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn retain_removes_matching_points_in_a_single_metadata_sync() {
+            // `retain` drives its removals through `delete_by_idx_unsynced` and syncs
+            // metadata once at the end, rather than once per removed point.
+            let path = scratch_path("retain-batches-sync");
+            let mut table = MmapTable::new(&path, 1, 8).unwrap();
+            for i in 0..10 {
+                table
+                    .put(&[1], DataPoint::Dense(vec![i as f32]))
+                    .unwrap();
+            }
+
+            table.retain(&|d| matches!(d, DataPoint::Dense(v) if v[0] < 5.0));
+
+            let bucket = table.query_bucket(&1, 0).unwrap().clone();
+            let mut remaining: Vec<f32> = bucket
+                .iter()
+                .map(|&idx| match table.idx_to_datapoint(idx) {
+                    DataPoint::Dense(v) => v[0],
+                    DataPoint::Sparse(_) => unreachable!(),
+                })
+                .collect();
+            remaining.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(remaining, vec![0.0, 1.0, 2.0, 3.0, 4.0]);
+            assert_eq!(table.free.len(), 5);
+
+            // The removal must actually have been persisted to disk, not just reflected
+            // in the in-memory `MmapTable`, proving metadata was synced after the sweep.
+            let reopened = MmapTable::open(&path).unwrap();
+            assert_eq!(reopened.free.len(), 5);
+            let bucket = reopened.query_bucket(&1, 0).unwrap().clone();
+            assert_eq!(bucket.len(), 5);
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+pub use mmap_table::MmapTable;